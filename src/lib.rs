@@ -2,6 +2,7 @@
 #![feature(core_intrinsics)]
 #![feature(generic_const_exprs)]
 #![feature(sync_unsafe_cell)]
+#![feature(allocator_api)]
 #![cfg_attr(not(test), no_std)]
 use core::fmt;
 
@@ -75,6 +76,82 @@ where
         Ok(())
     }
 
+    /// Returns the size of the block previously returned by `alloc` at `off`, without the
+    /// caller having to remember it.
+    ///
+    /// Walks from the leaf towards the root until the `USED` node allocated for `off` is
+    /// found; every node on the way up that isn't it has been overwritten by `push_up`.
+    #[allow(clippy::result_unit_err)]
+    pub fn alloc_size_of(&self, off: usize) -> Result<usize, ()> {
+        let i = self.find_used(off)?;
+        Ok(U << (H - i.ilog2() as u8))
+    }
+
+    /// Returns the actual block size that `alloc(size)` would grant, ie. `size` rounded up
+    /// to whatever level `lvl_for_size` maps it to. Lets a caller that already computed
+    /// `size` for an `alloc` call learn the real extent of the returned block without
+    /// walking the tree a second time via `alloc_size_of`.
+    #[allow(clippy::result_unit_err)]
+    pub fn block_size_for(size: usize) -> Result<usize, ()> {
+        let lvl = Self::lvl_for_size(size)?;
+        Ok(U << (H - lvl))
+    }
+
+    /// Like `dealloc`, but recovers `size` itself instead of requiring the caller to pass it.
+    #[allow(clippy::result_unit_err)]
+    pub fn dealloc_auto_size(&mut self, off: usize) -> Result<usize, ()> {
+        let i = self.find_used(off)?;
+        let size = U << (H - i.ilog2() as u8);
+        self.tree[i] = 0;
+        self.push_up(i);
+        Ok(size)
+    }
+
+    /// Attempts to resize the block at `off` to `new_size` without moving it. Succeeds
+    /// as a no-op (`Ok(None)`) when `new_size` still maps to the same level
+    /// (`lvl_for_size` is idempotent across the size class), and succeeds by freeing the
+    /// now-unused buddy subtrees when shrinking to a deeper level, returning the freed
+    /// `(offset, size)` byte range so the caller can do its own bookkeeping on it (eg.
+    /// decommitting the pages it covers). Fails, leaving the tree untouched, when growing
+    /// would require a shallower level, since the buddy may already be in use.
+    #[allow(clippy::result_unit_err)]
+    pub fn try_resize_in_place(
+        &mut self,
+        off: usize,
+        new_size: usize,
+    ) -> Result<Option<(usize, usize)>, ()> {
+        let i = self.find_used(off)?;
+        let old_lvl = i.ilog2() as u8;
+        let new_lvl = Self::lvl_for_size(new_size)?;
+        match new_lvl.cmp(&old_lvl) {
+            core::cmp::Ordering::Less => Err(()),
+            core::cmp::Ordering::Equal => Ok(None),
+            core::cmp::Ordering::Greater => {
+                self.tree[i] = 0;
+                let new_i = i << (new_lvl - old_lvl);
+                self.tree[new_i] = USED;
+                self.push_up(new_i);
+                let new_block_size = U << (H - new_lvl);
+                let old_block_size = U << (H - old_lvl);
+                Ok(Some((
+                    off + new_block_size,
+                    old_block_size - new_block_size,
+                )))
+            }
+        }
+    }
+
+    fn find_used(&self, off: usize) -> Result<usize, ()> {
+        let mut i = (1 << H) + off / U;
+        while self.tree[i] != USED {
+            if i <= 1 {
+                return Err(());
+            }
+            i >>= 1;
+        }
+        Ok(i)
+    }
+
     fn push_up(&mut self, mut i: usize) {
         while i > 1 {
             let a = self.tree[i];